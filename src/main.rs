@@ -1,16 +1,25 @@
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression as ZipCompression, ZipEntryBuilder};
 use clap::Parser;
 use env_logger::{Builder, WriteStyle};
 use futures::StreamExt;
 use log::{error, info, LevelFilter};
-use reqwest::header::HeaderMap;
-use reqwest::{Client, Url};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rand::Rng;
+use reqwest::header::{HeaderMap, ACCEPT_ENCODING, CONTENT_ENCODING, RETRY_AFTER};
+use reqwest::{Client, StatusCode, Url};
+use sha2::{Digest, Sha256};
 use std::error::Error;
-use std::io::Cursor;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::fs::File;
-use tokio::io::AsyncBufReadExt;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
 use tokio_stream::wrappers::LinesStream;
+use tokio_util::compat::FuturesAsyncWriteCompatExt;
 
 // Selon les version de KBART il y a deux types de header possible
 const KBART_HEADER : &'static str = "publication_title	print_identifier	online_identifier	date_first_issue_online	num_first_vol_online	num_first_issue_online	date_last_issue_online	num_last_vol_online	num_last_issue_online	title_url	first_author	title_id	embargo_info	coverage_depth	notes	publisher_name	publication_type";
@@ -32,7 +41,31 @@ struct Args {
     output_dir: String,
     /// Dont check kbart file validity
     #[arg(short,long, default_value_t = false)]
-    nocheck: bool
+    nocheck: bool,
+    /// Path to the SHA-256 manifest listing every downloaded file. Defaults to
+    /// `checksums.sha256` inside the output directory.
+    #[arg(long)]
+    manifest: Option<String>,
+    /// Disable the per-worker progress bars (useful for piped output or CI logs)
+    #[arg(long, default_value_t = false)]
+    no_progress: bool,
+    /// Store gzip/zstd compressed KBART files as-is instead of decompressing them
+    #[arg(long, default_value_t = false)]
+    keep_compressed: bool,
+    /// Number of times a retryable download failure is retried
+    #[arg(long, default_value_t = 3)]
+    retries: usize,
+    /// Base delay in seconds for the exponential backoff between retries
+    #[arg(long, default_value_t = 1)]
+    retry_wait_time: u64,
+    /// Bundle every harvested file into a single ZIP archive at this path, alongside an
+    /// `index.tsv` describing the harvest
+    #[arg(long)]
+    archive: Option<String>,
+    /// Write a JSON array summarizing the outcome of every URL (outcome, filename, HTTP
+    /// status, byte count, elapsed time) to this path
+    #[arg(long)]
+    report: Option<String>,
 }
 
 #[derive(Error, Debug)]
@@ -41,6 +74,12 @@ enum Errors {
     MissingPath(String),
     #[error("The kbart file must have a valid header")]
     InvalidKbartFile(String),
+    #[error("{url} answered with status {status}")]
+    HttpStatus {
+        url: String,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
 }
 
 #[tokio::main]
@@ -49,6 +88,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let workers = args.workers;
     let output_directory = PathBuf::from(args.output_dir);
     let check_validity = !args.nocheck;
+    let manifest_path = args
+        .manifest
+        .map(PathBuf::from)
+        .unwrap_or_else(|| output_directory.join("checksums.sha256"));
+    let multi_progress = if args.no_progress {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
 
     let mut builder = Builder::new();
 
@@ -64,11 +112,37 @@ async fn main() -> Result<(), Box<dyn Error>> {
             info!("reading data from stdin");
             let stdin = tokio::io::stdin();
             let reader = tokio::io::BufReader::new(stdin);
-            process(LinesStream::new(reader.lines()), output_directory, workers, check_validity).await;
+            process(
+                LinesStream::new(reader.lines()),
+                output_directory,
+                workers,
+                check_validity,
+                manifest_path,
+                multi_progress,
+                args.keep_compressed,
+                args.retries,
+                args.retry_wait_time,
+                args.archive.clone(),
+                args.report.clone(),
+            )
+            .await;
         }
         Some(file) => {
             let lines = read_lines(&file).await?;
-            process(LinesStream::new(lines), output_directory, workers, check_validity).await;
+            process(
+                LinesStream::new(lines),
+                output_directory,
+                workers,
+                check_validity,
+                manifest_path,
+                multi_progress,
+                args.keep_compressed,
+                args.retries,
+                args.retry_wait_time,
+                args.archive.clone(),
+                args.report.clone(),
+            )
+            .await;
         }
     }
 
@@ -82,6 +156,58 @@ async fn read_lines(
     Ok(tokio::io::BufReader::new(file).lines())
 }
 
+/// Compression appliquée par le serveur au fichier KBART, déduite du header
+/// `Content-Encoding` ou, à défaut, de l'extension de l'URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+fn detect_compression(url: &str, headers: &HeaderMap) -> Compression {
+    let content_encoding = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+
+    match content_encoding {
+        "gzip" => return Compression::Gzip,
+        "zstd" => return Compression::Zstd,
+        _ => {}
+    }
+
+    let path = Url::parse(url).ok().map(|u| u.path().to_string()).unwrap_or_default();
+    if path.ends_with(".gz") {
+        Compression::Gzip
+    } else if path.ends_with(".zst") {
+        Compression::Zstd
+    } else {
+        Compression::None
+    }
+}
+
+/// Décompresse le début d'un corps de réponse, pour permettre la comparaison avec
+/// `KBART_HEADER`/`KBART_HEADER_5321`. Les erreurs (flux tronqué, car on ne lit qu'une
+/// plage d'octets) sont ignorées : on compare ce qui a pu être décodé.
+async fn decompress_head(bytes: &[u8], compression: Compression) -> Vec<u8> {
+    let mut output = Vec::new();
+    match compression {
+        Compression::None => output.extend_from_slice(bytes),
+        Compression::Gzip => {
+            let mut decoder =
+                GzipDecoder::new(tokio::io::BufReader::new(std::io::Cursor::new(bytes.to_vec())));
+            let _ = decoder.read_to_end(&mut output).await;
+        }
+        Compression::Zstd => {
+            let mut decoder =
+                ZstdDecoder::new(tokio::io::BufReader::new(std::io::Cursor::new(bytes.to_vec())));
+            let _ = decoder.read_to_end(&mut output).await;
+        }
+    }
+    output
+}
+
 async fn check_header(url: &str) -> Result<(), Box<dyn Error>> {
     info!("checking kbart header of {}", url);
     let mut headers = HeaderMap::new();
@@ -95,7 +221,11 @@ async fn check_header(url: &str) -> Result<(), Box<dyn Error>> {
 
     let request = Client::new().get(url).headers(headers).build()?;
 
-    let response = Client::new().execute(request).await?.text().await?;
+    let response = Client::new().execute(request).await?;
+    let compression = detect_compression(url, response.headers());
+    let bytes = response.bytes().await?;
+    let decoded = decompress_head(&bytes, compression).await;
+    let response = String::from_utf8_lossy(&decoded);
 
     // Si le serveur ne supporte pas le byte range il retourne l'intégralité du document.
     // On vérifie donc que le header est présent avec starts_with et non avec une égalité parfaite.
@@ -107,47 +237,490 @@ async fn check_header(url: &str) -> Result<(), Box<dyn Error>> {
     }
 }
 
-async fn download(url: &str, file_path: PathBuf, check_file: bool) -> Result<(), Box<dyn Error>> {
+/// Le contenu décompressé ne doit pas hériter de l'extension de compression de l'URL d'origine
+/// (ex. `foo.kbart.gz` décompressé devient `foo.kbart`), sans quoi le fichier final ment sur
+/// son propre format.
+fn strip_compression_extension(file_path: &Path) -> PathBuf {
+    match file_path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") | Some("zst") => file_path.with_extension(""),
+        _ => file_path.to_path_buf(),
+    }
+}
+
+/// Chemin du fichier temporaire utilisé pendant le téléchargement, renommé vers `file_path`
+/// une fois le transfert terminé.
+fn part_path(file_path: &Path) -> PathBuf {
+    let mut part = file_path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Chemin du fichier temporaire utilisé le temps de décompresser `part` vers `file_path`,
+/// renommé atomiquement vers `file_path` une fois la décompression terminée.
+fn decompressed_part_path(file_path: &Path) -> PathBuf {
+    let mut part = file_path.as_os_str().to_owned();
+    part.push(".decompressed.part");
+    PathBuf::from(part)
+}
+
+/// Produit le fichier final (décompressé si besoin) à partir de `part`, qui contient toujours
+/// les octets bruts du corps HTTP, calcule son SHA-256 et l'ajoute au manifeste. Lecture et
+/// écriture se font par blocs : le contenu n'est jamais chargé en mémoire dans son entier.
+async fn finalize_download(
+    part: &Path,
+    file_path: &Path,
+    compression: Compression,
+    decompress_output: bool,
+    manifest: &Arc<Mutex<File>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 8192];
+
+    if decompress_output {
+        let raw = tokio::io::BufReader::new(File::open(part).await?);
+        let mut reader: Box<dyn AsyncRead + Send + Unpin> = match compression {
+            Compression::Gzip => Box::new(GzipDecoder::new(raw)),
+            Compression::Zstd => Box::new(ZstdDecoder::new(raw)),
+            Compression::None => Box::new(raw),
+        };
+
+        let decompressed_part = decompressed_part_path(file_path);
+        let mut writer = BufWriter::new(File::create(&decompressed_part).await?);
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            writer.write_all(&buffer[..read]).await?;
+        }
+        writer.flush().await?;
+        tokio::fs::rename(&decompressed_part, file_path).await?;
+        tokio::fs::remove_file(part).await?;
+    } else {
+        let mut reader = tokio::io::BufReader::new(File::open(part).await?);
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        tokio::fs::rename(part, file_path).await?;
+    }
+
+    let digest = hasher.finalize();
+    // Le nom du fichier n'existe que si file_path a été construit via process(), ce qui est
+    // toujours le cas : on peut donc s'appuyer sur file_name() sans crainte.
+    let filename = file_path.file_name().unwrap_or_default().to_string_lossy();
+
+    let mut manifest = manifest.lock().await;
+    manifest
+        .write_all(format!("{:x}  {}\n", digest, filename).as_bytes())
+        .await?;
+
+    Ok(())
+}
+
+fn bar_style() -> ProgressStyle {
+    ProgressStyle::with_template("{msg} [{bar:30.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("##-")
+}
+
+fn spinner_style() -> ProgressStyle {
+    ProgressStyle::with_template("{msg} {spinner} {bytes}").unwrap_or_else(|_| ProgressStyle::default_spinner())
+}
+
+async fn download(
+    url: &str,
+    file_path: PathBuf,
+    check_file: bool,
+    manifest: Arc<Mutex<File>>,
+    multi_progress: MultiProgress,
+    keep_compressed: bool,
+) -> Result<(u16, PathBuf), Box<dyn Error>> {
     if check_file {
         check_header(url).await?;
     }
 
+    let part = part_path(&file_path);
+    // `part` contient toujours les octets bruts du corps HTTP, jamais une version
+    // décompressée : sa taille indexe donc correctement ce qu'il faut demander via Range,
+    // que le fichier final soit décompressé ou non.
+    let existing_len = tokio::fs::metadata(&part).await.map(|m| m.len()).unwrap_or(0);
+
     info!("downloading {}", url);
-    let response = reqwest::get(url).await?;
-    let mut file = tokio::fs::File::create(file_path).await?;
-    let mut content = Cursor::new(response.bytes().await?);
-    tokio::io::copy(&mut content, &mut file).await?;
-    Ok(())
+    // On force `identity` pour être certain que le corps reçu correspond exactement à ce qui
+    // est écrit dans `part` : sans ça un serveur pourrait appliquer une compression de
+    // transport (Content-Encoding) non sollicitée, et une reprise indexerait alors le mauvais
+    // flux.
+    let mut request = Client::new().get(url).header(ACCEPT_ENCODING, "identity");
+    if existing_len > 0 {
+        request = request.header("Range", format!("bytes={}-", existing_len));
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() && response.status() != StatusCode::PARTIAL_CONTENT {
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        return Err(Errors::HttpStatus {
+            url: url.to_string(),
+            status,
+            retry_after,
+        }
+        .into());
+    }
+    let status_code = response.status().as_u16();
+    let resuming = existing_len > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+    let compression = detect_compression(url, response.headers());
+    let decompress_output = compression != Compression::None && !keep_compressed;
+
+    // Si le serveur ignore le Range et répond 200, le téléchargement repart de zéro : la
+    // position de départ et le total affichés ne doivent pas tenir compte du `.part` existant.
+    let offset = if resuming { existing_len } else { 0 };
+
+    let bar = match response.content_length() {
+        Some(len) => {
+            let bar = multi_progress.add(ProgressBar::new(offset + len));
+            bar.set_style(bar_style());
+            bar.set_position(offset);
+            bar
+        }
+        None => {
+            let bar = multi_progress.add(ProgressBar::new_spinner());
+            bar.set_style(spinner_style());
+            bar
+        }
+    };
+    bar.set_message(url.to_string());
+
+    let file = if resuming {
+        info!("resuming {} from byte {}", url, existing_len);
+        tokio::fs::OpenOptions::new().append(true).open(&part).await?
+    } else {
+        if existing_len > 0 {
+            info!(
+                "{} does not support range requests, restarting download from scratch",
+                url
+            );
+        }
+        File::create(&part).await?
+    };
+
+    let mut writer = BufWriter::new(file);
+
+    // `part` reçoit toujours les octets bruts du corps HTTP : la décompression, si
+    // nécessaire, n'intervient qu'une fois le fichier entièrement téléchargé.
+    let fetch_result: Result<(), Box<dyn Error>> = async {
+        let mut byte_stream = response.bytes_stream();
+        while let Some(chunk) = byte_stream.next().await {
+            let chunk = chunk?;
+            bar.inc(chunk.len() as u64);
+            writer.write_all(&chunk).await?;
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+    .await;
+
+    if let Err(error) = fetch_result {
+        bar.finish_with_message(format!("{} failed: {}", url, error));
+        return Err(error);
+    }
+
+    let final_file_path = if decompress_output {
+        strip_compression_extension(&file_path)
+    } else {
+        file_path.clone()
+    };
+
+    match finalize_download(&part, &final_file_path, compression, decompress_output, &manifest).await {
+        Ok(()) => {
+            bar.finish_with_message(format!("{} done", url));
+            Ok((status_code, final_file_path))
+        }
+        Err(error) => {
+            bar.finish_with_message(format!("{} failed: {}", url, error));
+            Err(error)
+        }
+    }
 }
 
+/// Un échec de connexion ou une réponse 429/5xx vaut la peine d'être retenté ; un 404 ou un
+/// header KBART invalide non, puisque retenter ne changera pas le résultat.
+fn is_retryable(error: &(dyn Error + 'static)) -> bool {
+    if let Some(Errors::HttpStatus { status, .. }) = error.downcast_ref::<Errors>() {
+        return *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+    }
+
+    let reqwest_error = error
+        .downcast_ref::<reqwest::Error>()
+        .or_else(|| {
+            error
+                .downcast_ref::<std::io::Error>()
+                .and_then(|io_error| io_error.get_ref())
+                .and_then(|source| source.downcast_ref::<reqwest::Error>())
+        });
+
+    match reqwest_error {
+        // `is_request()` couvre aussi les échecs de construction de requête et de politique de
+        // redirection, qui ne réussiront jamais au retry. `is_body()` couvre en revanche une
+        // connexion coupée en plein milieu du transfert — le mode de panne dominant sur les
+        // gros fichiers que la reprise via `.part` (chunk0-2) est censée couvrir.
+        Some(error) => error.is_timeout() || error.is_connect() || error.is_body(),
+        None => false,
+    }
+}
+
+fn retry_after(error: &(dyn Error + 'static)) -> Option<Duration> {
+    match error.downcast_ref::<Errors>() {
+        Some(Errors::HttpStatus { retry_after, .. }) => *retry_after,
+        _ => None,
+    }
+}
+
+/// Backoff exponentiel avec jitter : `retry_wait_time * 2^attempt`, plus une composante
+/// aléatoire pour éviter que des workers synchronisés ne retentent tous au même instant.
+fn backoff_delay(retry_wait_time: u64, attempt: u32) -> Duration {
+    let exponential = retry_wait_time.saturating_mul(1u64 << attempt.min(16));
+    let jitter = rand::thread_rng().gen_range(0..=retry_wait_time.max(1));
+    Duration::from_secs(exponential + jitter)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_with_retries(
+    url: &str,
+    file_path: PathBuf,
+    check_file: bool,
+    manifest: Arc<Mutex<File>>,
+    multi_progress: MultiProgress,
+    keep_compressed: bool,
+    retries: usize,
+    retry_wait_time: u64,
+) -> Result<(u16, PathBuf), Box<dyn Error>> {
+    let mut attempt = 0;
+
+    loop {
+        let result = download(
+            url,
+            file_path.clone(),
+            check_file,
+            manifest.clone(),
+            multi_progress.clone(),
+            keep_compressed,
+        )
+        .await;
+
+        let error = match result {
+            Ok(status) => return Ok(status),
+            Err(error) => error,
+        };
+
+        if attempt >= retries || !is_retryable(error.as_ref()) {
+            return Err(error);
+        }
+
+        let wait = retry_after(error.as_ref()).unwrap_or_else(|| backoff_delay(retry_wait_time, attempt as u32));
+        attempt += 1;
+        error!(
+            "{} failed ({}), retrying in {:?} (attempt {}/{})",
+            url, error, wait, attempt, retries
+        );
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Une ligne de `index.tsv`, décrivant un fichier du harvest inclus dans l'archive ZIP.
+struct IndexRecord {
+    url: String,
+    filename: String,
+    size: u64,
+    status: &'static str,
+}
+
+struct ArchiveState {
+    writer: Mutex<ZipFileWriter<File>>,
+    index: Mutex<Vec<IndexRecord>>,
+}
+
+/// Une ligne du rapport JSON produit par `--report`, décrivant le devenir d'une URL.
+#[derive(serde::Serialize)]
+struct HarvestOutcome {
+    url: String,
+    outcome: &'static str,
+    filename: Option<String>,
+    status: Option<u16>,
+    bytes: Option<u64>,
+    elapsed_ms: u128,
+}
+
+/// Classe une erreur de téléchargement selon les catégories attendues par le rapport.
+fn classify_error(error: &(dyn Error + 'static)) -> (&'static str, Option<u16>) {
+    match error.downcast_ref::<Errors>() {
+        Some(Errors::MissingPath(_)) => ("missing-path", None),
+        Some(Errors::InvalidKbartFile(_)) => ("header-invalid", None),
+        Some(Errors::HttpStatus { status, .. }) => ("network-error", Some(status.as_u16())),
+        None => ("network-error", None),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process<T: tokio_stream::Stream<Item = Result<String, std::io::Error>>>(
     stream: T,
     output_directory: PathBuf,
     workers: usize,
-    check_validity: bool
+    check_validity: bool,
+    manifest_path: PathBuf,
+    multi_progress: MultiProgress,
+    keep_compressed: bool,
+    retries: usize,
+    retry_wait_time: u64,
+    archive_path: Option<String>,
+    report_path: Option<String>,
 ) -> () {
+    let manifest = match tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&manifest_path)
+        .await
+    {
+        Ok(file) => Arc::new(Mutex::new(file)),
+        Err(error) => {
+            error!("unable to open manifest {:?}: {}", manifest_path, error);
+            return;
+        }
+    };
+
+    let archive = match archive_path {
+        Some(path) => match File::create(&path).await {
+            Ok(file) => Some(Arc::new(ArchiveState {
+                writer: Mutex::new(ZipFileWriter::with_tokio(file)),
+                index: Mutex::new(Vec::new()),
+            })),
+            Err(error) => {
+                error!("unable to create archive {}: {}", path, error);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let report: Option<Arc<Mutex<Vec<HarvestOutcome>>>> =
+        report_path.as_ref().map(|_| Arc::new(Mutex::new(Vec::new())));
+
     let fetches = stream
     .map(|line| {
         let output_directory = output_directory.clone();
+        let manifest = manifest.clone();
+        let multi_progress = multi_progress.clone();
+        let archive = archive.clone();
+        let report = report.clone();
         async move {
-            if let Ok(line) = line {
-                if !line.is_empty() {
-                    let url: Url = Url::parse(&line)?;
-                    let url_path = url
-                        .path_segments()
-                        .ok_or(Errors::MissingPath(line.clone()))?;
-
-                    let filename = url_path
-                        .last()
-                        .and_then(|path| if path.is_empty() { None } else { Some(path) })
-                        .map(sanitize_filename::sanitize)
-                        .map(|filename| output_directory.join(filename))
-                        .ok_or(Errors::MissingPath(line.clone()))?;
-
-                    download(&line, filename, check_validity).await?;
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return Ok(()),
+            };
+            if line.is_empty() {
+                return Ok(());
+            }
+
+            let start = std::time::Instant::now();
+
+            let outcome: Result<(u16, String), Box<dyn Error>> = async {
+                let url: Url = Url::parse(&line)?;
+                let url_path = url
+                    .path_segments()
+                    .ok_or(Errors::MissingPath(line.clone()))?;
+
+                let filename = url_path
+                    .last()
+                    .and_then(|path| if path.is_empty() { None } else { Some(path) })
+                    .map(sanitize_filename::sanitize)
+                    .ok_or(Errors::MissingPath(line.clone()))?;
+
+                let file_path = output_directory.join(&filename);
+
+                let (status, final_file_path) = download_with_retries(
+                    &line,
+                    file_path.clone(),
+                    check_validity,
+                    manifest,
+                    multi_progress,
+                    keep_compressed,
+                    retries,
+                    retry_wait_time,
+                )
+                .await?;
+                // Un fichier décompressé à la volée n'est plus écrit sous le nom tiré de
+                // l'URL (ex. `foo.kbart.gz`) : on doit se référer au nom effectif sur disque
+                // partout en aval (archive, manifeste, rapport).
+                let filename = final_file_path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned();
+
+                if let Some(archive) = archive {
+                    // On copie depuis le fichier déjà écrit sur disque plutôt que de charger
+                    // son contenu en mémoire : les KBART harvestés peuvent faire plusieurs
+                    // gigaoctets.
+                    let size = tokio::fs::metadata(&final_file_path).await?.len();
+                    let builder = ZipEntryBuilder::new(filename.clone(), ZipCompression::Deflate);
+                    {
+                        let mut zip_writer = archive.writer.lock().await;
+                        // `EntryStreamWriter` implémente `futures::AsyncWrite`, pas
+                        // `tokio::io::AsyncWrite` : on passe par l'adaptateur de compatibilité
+                        // pour pouvoir s'en servir comme sink de `tokio::io::copy`.
+                        let entry_writer = zip_writer.write_entry_stream(builder).await?;
+                        let mut compat_writer = entry_writer.compat_write();
+                        let mut source = File::open(&final_file_path).await?;
+                        tokio::io::copy(&mut source, &mut compat_writer).await?;
+                        compat_writer.into_inner().close().await?;
+                    }
+                    archive.index.lock().await.push(IndexRecord {
+                        url: line.clone(),
+                        filename: filename.clone(),
+                        size,
+                        status: if check_validity { "valid" } else { "unchecked" },
+                    });
                 }
+
+                Ok((status, filename))
+            }
+            .await;
+
+            if let Some(report) = report {
+                let elapsed_ms = start.elapsed().as_millis();
+                let (outcome_kind, status, filename, bytes) = match &outcome {
+                    Ok((status, filename)) => {
+                        let bytes = tokio::fs::metadata(output_directory.join(filename))
+                            .await
+                            .ok()
+                            .map(|metadata| metadata.len());
+                        ("downloaded", Some(*status), Some(filename.clone()), bytes)
+                    }
+                    Err(error) => {
+                        let (outcome_kind, status) = classify_error(error.as_ref());
+                        (outcome_kind, status, None, None)
+                    }
+                };
+
+                report.lock().await.push(HarvestOutcome {
+                    url: line.clone(),
+                    outcome: outcome_kind,
+                    filename,
+                    status,
+                    bytes,
+                    elapsed_ms,
+                });
             }
-            Ok(())
+
+            outcome.map(|_| ())
         }
     })
     .buffer_unordered(workers)
@@ -158,4 +731,134 @@ for elem in fetches.await {
         error!("{}", error)
     }
 }
+
+if let (Some(report_path), Some(report)) = (&report_path, &report) {
+    let outcomes = report.lock().await;
+    match serde_json::to_vec_pretty(&*outcomes) {
+        Ok(json) => {
+            if let Err(error) = tokio::fs::write(report_path, json).await {
+                error!("unable to write report {}: {}", report_path, error);
+            }
+        }
+        Err(error) => error!("unable to serialize report: {}", error),
+    }
+}
+
+if let Some(archive) = archive {
+    match Arc::try_unwrap(archive) {
+        Ok(ArchiveState { writer, index }) => {
+            let mut writer = writer.into_inner();
+            let mut tsv = String::from("url\tfilename\tsize\tstatus\n");
+            for record in index.into_inner() {
+                tsv.push_str(&format!(
+                    "{}\t{}\t{}\t{}\n",
+                    record.url, record.filename, record.size, record.status
+                ));
+            }
+
+            let builder = ZipEntryBuilder::new("index.tsv".to_string(), ZipCompression::Deflate);
+            if let Err(error) = writer.write_entry_whole(builder, tsv.as_bytes()).await {
+                error!("unable to write archive index: {}", error);
+            }
+            if let Err(error) = writer.close().await {
+                error!("unable to finalize archive: {}", error);
+            }
+        }
+        Err(_) => error!("unable to finalize archive: still in use"),
+    }
+}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_compression_prefers_content_encoding_over_url() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+        assert_eq!(
+            detect_compression("https://example.org/file.zst", &headers),
+            Compression::Gzip
+        );
+    }
+
+    #[test]
+    fn detect_compression_falls_back_to_url_extension() {
+        let headers = HeaderMap::new();
+        assert_eq!(detect_compression("https://example.org/file.gz", &headers), Compression::Gzip);
+        assert_eq!(detect_compression("https://example.org/file.zst", &headers), Compression::Zstd);
+        assert_eq!(detect_compression("https://example.org/file.kbart", &headers), Compression::None);
+    }
+
+    #[test]
+    fn is_retryable_accepts_429_and_5xx() {
+        let too_many = Errors::HttpStatus {
+            url: "https://example.org".to_string(),
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after: None,
+        };
+        let server_error = Errors::HttpStatus {
+            url: "https://example.org".to_string(),
+            status: StatusCode::BAD_GATEWAY,
+            retry_after: None,
+        };
+        let not_found = Errors::HttpStatus {
+            url: "https://example.org".to_string(),
+            status: StatusCode::NOT_FOUND,
+            retry_after: None,
+        };
+
+        assert!(is_retryable(&too_many));
+        assert!(is_retryable(&server_error));
+        assert!(!is_retryable(&not_found));
+    }
+
+    #[test]
+    fn is_retryable_rejects_non_retryable_errors() {
+        let invalid_kbart = Errors::InvalidKbartFile("https://example.org".to_string());
+        let missing_path = Errors::MissingPath("https://example.org".to_string());
+
+        assert!(!is_retryable(&invalid_kbart));
+        assert!(!is_retryable(&missing_path));
+    }
+
+    #[test]
+    fn retry_after_reads_http_status_hint() {
+        let with_hint = Errors::HttpStatus {
+            url: "https://example.org".to_string(),
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after: Some(Duration::from_secs(30)),
+        };
+        let without_hint = Errors::InvalidKbartFile("https://example.org".to_string());
+
+        assert_eq!(retry_after(&with_hint), Some(Duration::from_secs(30)));
+        assert_eq!(retry_after(&without_hint), None);
+    }
+
+    #[test]
+    fn backoff_delay_grows_exponentially_and_stays_bounded_by_jitter() {
+        let first = backoff_delay(1, 0);
+        let second = backoff_delay(1, 1);
+        let third = backoff_delay(1, 2);
+
+        assert!(first.as_secs() >= 1 && first.as_secs() <= 2);
+        assert!(second.as_secs() >= 2 && second.as_secs() <= 3);
+        assert!(third.as_secs() >= 4 && third.as_secs() <= 5);
+    }
+
+    #[test]
+    fn classify_error_matches_report_categories() {
+        let missing_path = Errors::MissingPath("https://example.org".to_string());
+        let invalid_kbart = Errors::InvalidKbartFile("https://example.org".to_string());
+        let http_status = Errors::HttpStatus {
+            url: "https://example.org".to_string(),
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            retry_after: None,
+        };
+
+        assert_eq!(classify_error(&missing_path), ("missing-path", None));
+        assert_eq!(classify_error(&invalid_kbart), ("header-invalid", None));
+        assert_eq!(classify_error(&http_status), ("network-error", Some(503)));
+    }
 }